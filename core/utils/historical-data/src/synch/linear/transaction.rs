@@ -29,16 +29,81 @@
 //! of transaction) or a reference to the transaction counter in use in time of creation.
 
 use rstd::vec::Vec;
+use rstd::cell::Cell;
+use rstd::mem::size_of;
+use codec::{Decode, Encode};
 use crate::PruneResult;
 
-/// Global state is a simple counter to the current overlay layer index.
-#[derive(Debug, Clone)]
+/// Observes mutations to a `History` as they happen, so a caller can
+/// maintain a dirty-key index, emit metrics on discarded prospective
+/// writes, or drive cache invalidation without re-scanning the whole
+/// overlay after every transaction boundary.
+///
+/// All methods default to doing nothing, so implementors only need to
+/// override the events they actually care about.
+pub trait HistoryListener<V> {
+	/// A value was written at the current layer, `old` being what was
+	/// visible beforehand (`None` for a key seen for the first time).
+	fn on_set<C>(&mut self, _key_ctx: &C, _old: Option<&V>, _new: &V) {}
+
+	/// Every layer above `layer` was just committed into `layer`.
+	fn on_commit(&mut self, _layer: usize) {}
+
+	/// Layer `layer` was just discarded.
+	fn on_discard(&mut self, _layer: usize) {}
+
+	/// A `History` was pruned following a commit or discard.
+	fn on_prune(&mut self, _result: PruneResult) {}
+}
+
+/// A `HistoryListener` that ignores every event, used where no listener
+/// is supplied.
+pub struct NoopListener;
+impl<V> HistoryListener<V> for NoopListener {}
+
+/// Capacity limits applied to an overlay sharing a single `States`.
+///
+/// `max_layers` is not enforced by this module: it is only exposed for
+/// the embedding storage layer to consult before calling
+/// `start_transaction`. `max_history_per_key` is applied by
+/// `History::set`, within the limits described on that field.
+#[derive(Debug, Clone, Default)]
 #[cfg_attr(any(test, feature = "test-helpers"), derive(PartialEq))]
-pub struct States(usize);
-	
+pub struct StatesConfig {
+	/// Maximum number of nested transactional layers.
+	pub max_layers: Option<usize>,
+	/// Maximum number of `HistoricalValue` retained per key. Once a
+	/// `History` would grow past it, `State::Transaction(0)` entries that
+	/// directly follow its committed entry are folded into that committed
+	/// slot. Entries of other layers are never folded, so a `History`
+	/// can still hold more entries than this.
+	pub max_history_per_key: Option<usize>,
+}
+
+/// Global state is a simple counter to the current overlay layer index,
+/// together with the capacity configuration applied to every `History`
+/// that shares it and a running count of entries retained under it.
+#[derive(Debug, Clone)]
+pub struct States {
+	current_layer: usize,
+	config: StatesConfig,
+	retained_entries: Cell<usize>,
+}
+
+#[cfg(any(test, feature = "test-helpers"))]
+impl PartialEq for States {
+	fn eq(&self, other: &Self) -> bool {
+		self.current_layer == other.current_layer && self.config == other.config
+	}
+}
+
 impl Default for States {
 	fn default() -> Self {
-		States(0)
+		States {
+			current_layer: 0,
+			config: Default::default(),
+			retained_entries: Cell::new(0),
+		}
 	}
 }
 
@@ -49,76 +114,289 @@ impl States {
 	pub fn test_state(
 		current_layer_number: usize,
 	) -> Self {
-		States(current_layer_number)
+		States {
+			current_layer: current_layer_number,
+			..Default::default()
+		}
+	}
+
+	/// Build a `States` bounded by `config`.
+	pub fn with_config(config: StatesConfig) -> Self {
+		States {
+			config,
+			..Default::default()
+		}
+	}
+
+	/// Running count of `HistoricalValue` entries retained across every
+	/// key sharing this `States`. The embedding storage layer can use it
+	/// to decide when to force-commit prospective work.
+	pub fn retained_entries(&self) -> usize {
+		self.retained_entries.get()
+	}
+
+	/// Maximum number of nested transactional layers configured for this
+	/// overlay, for the embedding storage layer to consult before
+	/// calling `start_transaction`.
+	pub fn max_layers(&self) -> Option<usize> {
+		self.config.max_layers
+	}
+
+	fn record_retained_delta(&self, removed: usize, added: usize) {
+		let current = self.retained_entries.get();
+		self.retained_entries.set(current + added - removed);
 	}
 
 	/// Discard prospective changes to state.
-	/// It does not reverts actual values. 
+	/// It does not reverts actual values.
 	/// A subsequent synchronisation of stored values is needed.
 	pub fn discard_prospective(&mut self) {
-		if self.0 > 0 {
-			self.0 -= 1;
+		if self.current_layer > 0 {
+			self.current_layer -= 1;
 		}
 	}
 
-	/// Update a value to a new prospective.
-	pub fn apply_discard_prospective(&self) {
-		unimplemented!("TODO History as mut param");
+	/// Synchronise a `History` with a prior call to `discard_prospective`,
+	/// dropping every value that was only visible in the discarded layers.
+	pub fn apply_discard_prospective<V>(&self, history: &mut History<V>) -> PruneResult {
+		self.apply_discard_prospective_with_listener::<V, NoopListener>(history, None)
+	}
+
+	/// Like `apply_discard_prospective`, additionally notifying `listener`
+	/// of the discard.
+	pub fn apply_discard_prospective_with_listener<V, L: HistoryListener<V>>(
+		&self,
+		history: &mut History<V>,
+		mut listener: Option<&mut L>,
+	) -> PruneResult {
+		let discarded_layer = self.current_layer + 1;
+		let removed = history.prune_discarded(discarded_layer);
+		self.record_retained_delta(removed, 0);
+		if let Some(l) = &mut listener {
+			l.on_discard(discarded_layer);
+			l.on_prune(history.prune_result());
+		}
+		history.prune_result()
 	}
 
 	/// Commit prospective changes to state.
 	/// A subsequent synchronisation of stored values is needed.
 	pub fn commit_prospective(&mut self) {
-		if self.0 > 0 {
-			self.0 -= 1;
+		if self.current_layer > 0 {
+			self.current_layer -= 1;
 		}
 	}
 
-	/// Update a value to a new prospective.
+	/// Synchronise a `History` with a prior call to `commit_prospective`.
 	/// Multiple commit can be applied at the same time.
-	pub fn apply_commit_prospective(&self) {
-		unimplemented!("TODO History as mut param");
+	pub fn apply_commit_prospective<V>(&self, history: &mut History<V>) -> PruneResult {
+		self.apply_commit_prospective_with_listener::<V, NoopListener>(history, None)
+	}
+
+	/// Like `apply_commit_prospective`, additionally notifying `listener`
+	/// of the commit.
+	pub fn apply_commit_prospective_with_listener<V, L: HistoryListener<V>>(
+		&self,
+		history: &mut History<V>,
+		mut listener: Option<&mut L>,
+	) -> PruneResult {
+		let removed = history.prune_committed(self.current_layer);
+		self.record_retained_delta(removed, 0);
+		if let Some(l) = &mut listener {
+			l.on_commit(self.current_layer);
+			l.on_prune(history.prune_result());
+		}
+		history.prune_result()
+	}
+
+	/// Like `apply_commit_prospective_with_listener`, additionally feeding
+	/// the value newly promoted to `State::Committed` (if any) into
+	/// `accumulator` under `key`, so callers wiring up proofs do not need
+	/// to call `History::feed_committed` themselves after every commit.
+	pub fn apply_commit_prospective_into_accumulator<V: Encode, L: HistoryListener<V>>(
+		&self,
+		history: &mut History<V>,
+		key: &[u8],
+		accumulator: &mut Accumulator,
+		listener: Option<&mut L>,
+	) -> PruneResult {
+		let had_committed_top = match history.0.last() {
+			Some(HistoricalValue { index: State::Committed, .. }) => true,
+			_ => false,
+		};
+		let result = self.apply_commit_prospective_with_listener(history, listener);
+		if self.current_layer == 0 && !had_committed_top {
+			history.feed_committed(key, accumulator);
+		}
+		result
 	}
 
 
 	/// Create a new transactional layer.
 	pub fn start_transaction(&mut self) {
-		self.0 += 1;
+		self.current_layer += 1;
 	}
 
 	/// Discard a transactional layer.
 	/// It does not reverts actual values.
 	/// A subsequent synchronisation of stored values is needed.
 	pub fn discard_transaction(&mut self) {
-		if self.0 > 0 {
-			self.0 -= 1;
+		if self.current_layer > 0 {
+			self.current_layer -= 1;
 		}
 	}
 
-	/// Update a value to previous transaction.
+	/// Synchronise a `History` with a prior call to `discard_transaction`.
 	/// Multiple discard can be applied at the same time.
-	/// Returns true if value is still needed.
-	pub fn apply_discard_transaction(&self) -> PruneResult {
-		unimplemented!("TODO History as mut param");
+	/// Returns whether the history still holds a reachable value, so the
+	/// caller can evict the key from its parent map when it does not.
+	pub fn apply_discard_transaction<V>(&self, history: &mut History<V>) -> PruneResult {
+		self.apply_discard_transaction_with_listener::<V, NoopListener>(history, None)
+	}
+
+	/// Like `apply_discard_transaction`, additionally notifying `listener`
+	/// of the discard.
+	pub fn apply_discard_transaction_with_listener<V, L: HistoryListener<V>>(
+		&self,
+		history: &mut History<V>,
+		mut listener: Option<&mut L>,
+	) -> PruneResult {
+		let discarded_layer = self.current_layer + 1;
+		let removed = history.prune_discarded(discarded_layer);
+		self.record_retained_delta(removed, 0);
+		if let Some(l) = &mut listener {
+			l.on_discard(discarded_layer);
+			l.on_prune(history.prune_result());
+		}
+		history.prune_result()
 	}
 
 	/// Discard a transactional layer.
 	/// It does not reverts actual values.
 	/// A subsequent synchronisation of stored values is needed.
 	pub fn commit_transaction(&mut self) {
-		if self.0 > 0 {
-			self.0 -= 1;
+		if self.current_layer > 0 {
+			self.current_layer -= 1;
 		}
 	}
 
-	/// Update a value to be the best historical value
-	/// after one or more `commit_transaction` calls.
-	/// Multiple discard can be applied at the same time.
-	/// Returns true if value is still needed.
-	pub fn apply_commit_transaction(&self) -> PruneResult {
-		unimplemented!("TODO History as mut param");
+	/// Synchronise a `History` with one or more prior calls to
+	/// `commit_transaction`, rewriting its surviving top entry down to the
+	/// current layer (or to `State::Committed` when it reaches layer `0`).
+	/// Multiple commit can be applied at the same time.
+	/// Returns whether the history still holds a reachable value, so the
+	/// caller can evict the key from its parent map when it does not.
+	pub fn apply_commit_transaction<V>(&self, history: &mut History<V>) -> PruneResult {
+		self.apply_commit_transaction_with_listener::<V, NoopListener>(history, None)
+	}
+
+	/// Like `apply_commit_transaction`, additionally notifying `listener`
+	/// of the commit.
+	pub fn apply_commit_transaction_with_listener<V, L: HistoryListener<V>>(
+		&self,
+		history: &mut History<V>,
+		mut listener: Option<&mut L>,
+	) -> PruneResult {
+		let removed = history.prune_committed(self.current_layer);
+		self.record_retained_delta(removed, 0);
+		if let Some(l) = &mut listener {
+			l.on_commit(self.current_layer);
+			l.on_prune(history.prune_result());
+		}
+		history.prune_result()
+	}
+
+	/// Like `apply_commit_transaction_with_listener`, additionally feeding
+	/// the value newly promoted to `State::Committed` (if any) into
+	/// `accumulator` under `key`, so callers wiring up proofs do not need
+	/// to call `History::feed_committed` themselves after every commit.
+	pub fn apply_commit_transaction_into_accumulator<V: Encode, L: HistoryListener<V>>(
+		&self,
+		history: &mut History<V>,
+		key: &[u8],
+		accumulator: &mut Accumulator,
+		listener: Option<&mut L>,
+	) -> PruneResult {
+		let had_committed_top = match history.0.last() {
+			Some(HistoricalValue { index: State::Committed, .. }) => true,
+			_ => false,
+		};
+		let result = self.apply_commit_transaction_with_listener(history, listener);
+		if self.current_layer == 0 && !had_committed_top {
+			history.feed_committed(key, accumulator);
+		}
+		result
+	}
+
+	/// Canonical, deterministic binary encoding of the layer counter and
+	/// configuration, so a `States` can be persisted alongside the
+	/// `History::encode_canonical` of the keys it governs across a
+	/// snapshot/restore boundary. `retained_entries` is not encoded.
+	pub fn encode_canonical(&self) -> Vec<u8> {
+		let mut out = Vec::new();
+		out.extend_from_slice(&(self.current_layer as u64).to_le_bytes());
+		encode_canonical_option(&mut out, self.config.max_layers);
+		encode_canonical_option(&mut out, self.config.max_history_per_key);
+		out
+	}
+
+	/// Decode a `States` previously produced by `encode_canonical`.
+	/// `retained_entries` starts back at `0`, so it does not count the
+	/// entries of any `History` restored alongside this `States`.
+	pub fn decode_canonical(input: &[u8]) -> Result<Self, StatesDecodeError> {
+		let mut input = input;
+		let current_layer = decode_canonical_u64(&mut input)? as usize;
+		let max_layers = decode_canonical_option(&mut input)?.map(|v| v as usize);
+		let max_history_per_key = decode_canonical_option(&mut input)?.map(|v| v as usize);
+		Ok(States {
+			current_layer,
+			config: StatesConfig { max_layers, max_history_per_key },
+			retained_entries: Cell::new(0),
+		})
+	}
+
+}
+
+fn encode_canonical_option(out: &mut Vec<u8>, value: Option<usize>) {
+	match value {
+		Some(v) => {
+			out.push(1u8);
+			out.extend_from_slice(&(v as u64).to_le_bytes());
+		},
+		None => out.push(0u8),
+	}
+}
+
+fn decode_canonical_u64(input: &mut &[u8]) -> Result<u64, StatesDecodeError> {
+	if input.len() < 8 {
+		return Err(StatesDecodeError::UnexpectedEof);
+	}
+	let mut buf = [0u8; 8];
+	buf.copy_from_slice(&input[..8]);
+	*input = &input[8..];
+	Ok(u64::from_le_bytes(buf))
+}
+
+fn decode_canonical_option(input: &mut &[u8]) -> Result<Option<u64>, StatesDecodeError> {
+	if input.is_empty() {
+		return Err(StatesDecodeError::UnexpectedEof);
 	}
+	let discriminant = input[0];
+	*input = &input[1..];
+	match discriminant {
+		0 => Ok(None),
+		1 => decode_canonical_u64(input).map(Some),
+		other => Err(StatesDecodeError::InvalidDiscriminant(other)),
+	}
+}
 
+/// Failure modes of `States::decode_canonical`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum StatesDecodeError {
+	/// The input ended before a complete field could be read.
+	UnexpectedEof,
+	/// An `Option` discriminant byte did not match a known variant.
+	InvalidDiscriminant(u8),
 }
 
 /// Possible state for a historical value, committed
@@ -156,20 +434,46 @@ impl<V> Default for History<V> {
 impl<V> History<V> {
 	/// Set a value, it uses a global state as parameter.
 	pub fn set(&mut self, states: &States, value: V) {
+		self.set_with_listener::<(), NoopListener>(states, &(), value, None)
+	}
+
+	/// Set a value like `set`, additionally notifying `listener` of the
+	/// write. `key_ctx` identifies the key this history belongs to, for
+	/// listeners that key their own state off of it.
+	pub fn set_with_listener<C, L: HistoryListener<V>>(
+		&mut self,
+		states: &States,
+		key_ctx: &C,
+		value: V,
+		mut listener: Option<&mut L>,
+	) {
+		if let Some(l) = &mut listener {
+			l.on_set(key_ctx, self.0.last().map(|h| &h.value), &value);
+		}
 		if let Some(v) = self.0.last_mut() {
-			debug_assert!(v.index.transaction_index().unwrap_or(0) <= states.0,
+			debug_assert!(v.index.transaction_index().unwrap_or(0) <= states.current_layer,
 				"History expects \
 				only new values at the latest state, some state has not \
 				synchronized properly");
-			if v.index.transaction_index() == Some(states.0) {
+			if v.index.transaction_index() == Some(states.current_layer) {
 				v.value = value;
 				return;
 			}
 		}
+		let folded = match states.config.max_history_per_key {
+			Some(max) => self.collapse_to_capacity(max),
+			None => 0,
+		};
+		if folded > 0 {
+			if let Some(l) = &mut listener {
+				l.on_prune(self.prune_result());
+			}
+		}
 		self.0.push(HistoricalValue {
 			value,
-			index: State::Transaction(states.0),
+			index: State::Transaction(states.current_layer),
 		});
+		states.record_retained_delta(folded, 1);
 	}
 
 	/// Access to the latest pending value.
@@ -239,4 +543,701 @@ impl<V> History<V> {
 		self.0.last_mut().map(|h| h.as_mut())
 	}
 
+	/// Walk every retained value, oldest first, paired with its effective
+	/// layer (`State::Committed` as layer `0`, `State::Transaction(ix)`
+	/// as `ix`), newest last. Lets callers reconstruct the full
+	/// prospective stack of a storage slot without consuming the
+	/// `History`, e.g. for speculative execution or diagnostics.
+	pub fn iter_pending(&self) -> impl Iterator<Item = (usize, &V)> {
+		self.0.iter().map(|h| (h.index.transaction_index().unwrap_or(0), &h.value))
+	}
+
+	/// The value this key would read as if rolled back to `layer`: the
+	/// latest retained entry whose effective layer is `<= layer`, or
+	/// `None` if the key had not been written yet at that depth.
+	pub fn iter_layer(&self, layer: usize) -> Option<&V> {
+		self.0.iter()
+			.rev()
+			.find(|h| h.index.transaction_index().unwrap_or(0) <= layer)
+			.map(|h| &h.value)
+	}
+
+	/// Drop every trailing value that belonged to a layer at or above
+	/// `discarded_layer`, which is no longer reachable once that layer
+	/// has been discarded. Returns the number of entries removed.
+	fn prune_discarded(&mut self, discarded_layer: usize) -> usize {
+		let mut removed = 0;
+		while self.0.last().and_then(|h| h.index.transaction_index())
+			.map(|ix| ix >= discarded_layer).unwrap_or(false)
+		{
+			self.0.pop();
+			removed += 1;
+		}
+		removed
+	}
+
+	/// Rewrite every trailing entry whose layer is no longer reachable
+	/// (strictly above `current_layer`) down to a single entry tagged
+	/// `current_layer` (or `State::Committed` when `current_layer` is
+	/// `0`), keeping only the most recent of the collapsed values, and
+	/// coalescing it with an already-reachable entry at that same layer
+	/// so at most one `State::Committed` record remains. Also promotes
+	/// the surviving top entry in place when it already sits at layer
+	/// `0` but was never reopened above it, so a value set once and
+	/// immediately committed still becomes `State::Committed`. Returns
+	/// the number of entries removed.
+	fn prune_committed(&mut self, current_layer: usize) -> usize {
+		let mut removed = 0;
+		let mut carried = None;
+		while let Some(ix) = self.0.last().and_then(|h| h.index.transaction_index()) {
+			if ix <= current_layer {
+				break;
+			}
+			carried.get_or_insert(self.0.pop().unwrap().value);
+			removed += 1;
+		}
+		match carried {
+			Some(value) => {
+				let new_index = if current_layer == 0 { State::Committed } else { State::Transaction(current_layer) };
+				let reaches_same_layer = match self.0.last() {
+					Some(HistoricalValue { index: State::Committed, .. }) => current_layer == 0,
+					Some(HistoricalValue { index: State::Transaction(ix), .. }) => *ix == current_layer,
+					None => false,
+				};
+				if reaches_same_layer {
+					let top = self.0.last_mut().expect("reaches_same_layer implies a last entry; qed");
+					top.value = value;
+					top.index = new_index;
+				} else {
+					self.0.push(HistoricalValue { value, index: new_index });
+				}
+			},
+			// Nothing was popped above: the surviving top entry is already
+			// at `current_layer`. It still needs promoting in place when
+			// `current_layer` is `0` and it was never reopened above
+			// layer `0`, since such a value was only ever written at the
+			// layer it is now being committed from and so was pushed as
+			// `Transaction(0)`, never rewritten to `Committed`.
+			None if current_layer == 0 => {
+				if let Some(top) = self.0.last_mut() {
+					if top.index.transaction_index() == Some(0) {
+						top.index = State::Committed;
+					}
+				}
+			},
+			None => {},
+		}
+		if self.0.len() >= 2 {
+			if let (State::Committed, State::Committed) = (&self.0[0].index, &self.0[1].index) {
+				self.0.remove(0);
+				removed += 1;
+			}
+		}
+		removed
+	}
+
+	/// Collapse the oldest entry into an existing committed slot until
+	/// this history holds fewer than `max` entries. Only entries tagged
+	/// `State::Transaction(0)` are folded: such an entry can never be
+	/// exposed again by a `discard_transaction` (the layer counter never
+	/// goes below `0`), so it is provably unreachable the moment a
+	/// `State::Committed` entry precedes it, unlike entries from layers
+	/// still open relative to the current nesting depth. Returns the
+	/// number of entries removed.
+	fn collapse_to_capacity(&mut self, max: usize) -> usize {
+		let mut folded = 0;
+		while self.0.len() >= max && self.0.len() > 1
+			&& self.0[0].index.transaction_index().is_none()
+			&& self.0[1].index.transaction_index() == Some(0)
+		{
+			let next = self.0.remove(1);
+			self.0[0].value = next.value;
+			self.0[0].index = State::Committed;
+			folded += 1;
+		}
+		folded
+	}
+
+	/// Whether this history still holds a reachable value, for callers
+	/// that need to evict now-empty keys from their parent map.
+	fn prune_result(&self) -> PruneResult {
+		if self.0.is_empty() {
+			PruneResult::Cleared
+		} else {
+			PruneResult::Kept
+		}
+	}
+
+	/// Rough estimate, in bytes, of the memory retained by this history.
+	pub fn memory_footprint(&self) -> usize {
+		self.0.capacity() * size_of::<HistoricalValue<V>>()
+	}
+
+	/// Canonical, deterministic binary encoding: two equal histories
+	/// always produce byte-identical output, so the result can be
+	/// diffed, hashed, and round-tripped with `decode_canonical`.
+	///
+	/// Each entry is encoded, in layer order, as its `State` discriminant
+	/// (`0` for `Committed`, `1` for `Transaction`), the transaction index
+	/// when present, then the codec-encoded value prefixed by its length.
+	pub fn encode_canonical(&self) -> Vec<u8> where V: Encode {
+		let mut out = Vec::new();
+		for entry in &self.0 {
+			match entry.index {
+				State::Committed => out.push(0u8),
+				State::Transaction(ix) => {
+					out.push(1u8);
+					out.extend_from_slice(&(ix as u64).to_le_bytes());
+				},
+			}
+			let encoded = entry.value.encode();
+			out.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+			out.extend_from_slice(&encoded);
+		}
+		out
+	}
+
+	/// Decode a `History` previously produced by `encode_canonical`,
+	/// validating the monotonic-non-decreasing transaction-index
+	/// invariant that `set` otherwise only `debug_assert!`s, and
+	/// returning an error instead of panicking on malformed input.
+	pub fn decode_canonical(input: &[u8]) -> Result<Self, CanonicalDecodeError> where V: Decode {
+		let mut input = input;
+		let mut entries = Vec::new();
+		let mut last_layer = None;
+		while !input.is_empty() {
+			let discriminant = input[0];
+			input = &input[1..];
+			let index = match discriminant {
+				0 => State::Committed,
+				1 => {
+					if input.len() < 8 {
+						return Err(CanonicalDecodeError::UnexpectedEof);
+					}
+					let mut buf = [0u8; 8];
+					buf.copy_from_slice(&input[..8]);
+					input = &input[8..];
+					State::Transaction(u64::from_le_bytes(buf) as usize)
+				},
+				other => return Err(CanonicalDecodeError::InvalidState(other)),
+			};
+			let layer = index.transaction_index().unwrap_or(0);
+			if last_layer.map(|last| layer < last).unwrap_or(false) {
+				return Err(CanonicalDecodeError::NonMonotonicLayers);
+			}
+			last_layer = Some(layer);
+			if input.len() < 4 {
+				return Err(CanonicalDecodeError::UnexpectedEof);
+			}
+			let mut len_buf = [0u8; 4];
+			len_buf.copy_from_slice(&input[..4]);
+			input = &input[4..];
+			let len = u32::from_le_bytes(len_buf) as usize;
+			if input.len() < len {
+				return Err(CanonicalDecodeError::UnexpectedEof);
+			}
+			let value = V::decode(&mut &input[..len])
+				.ok_or(CanonicalDecodeError::InvalidValue)?;
+			input = &input[len..];
+			entries.push(HistoricalValue { value, index });
+		}
+		Ok(History(entries))
+	}
+
+}
+
+/// Failure modes of `History::decode_canonical`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CanonicalDecodeError {
+	/// The input ended before a complete entry could be read.
+	UnexpectedEof,
+	/// A `State` discriminant byte did not match a known variant.
+	InvalidState(u8),
+	/// The codec-encoded value could not be decoded.
+	InvalidValue,
+	/// Transaction indices were not monotonically non-decreasing across
+	/// layers, violating the invariant `History::set` relies on.
+	NonMonotonicLayers,
+}
+
+impl<V: Encode> History<V> {
+	/// If this history's oldest retained entry is `State::Committed` —
+	/// typically right after `apply_commit_prospective` or
+	/// `apply_commit_transaction` promoted it — feed it as a leaf into
+	/// `accumulator`, keyed by `key`, and return the updated root.
+	///
+	/// Returns `None` when there is nothing committed yet (the key is
+	/// still purely prospective).
+	pub fn feed_committed(&self, key: &[u8], accumulator: &mut Accumulator) -> Option<Hash> {
+		match self.0.get(0) {
+			Some(HistoricalValue { value, index: State::Committed }) => {
+				Some(accumulator.commit(key, value))
+			},
+			_ => None,
+		}
+	}
+}
+
+/// 256-bit digest produced by the accumulator below.
+pub type Hash = [u8; 32];
+
+/// A dependency-free, deterministic 256-bit mixing hash. Swap for the
+/// embedding runtime's configured hasher; what matters here is that it
+/// is deterministic and mixes its input well enough to exercise the
+/// accumulator and its proofs.
+fn hash_bytes(data: &[u8]) -> Hash {
+	let mut state = [0x9E3779B97F4A7C15u64, 0xC2B2AE3D27D4EB4Fu64, 0x165667B19E3779F9u64, 0x85EBCA77C2B2AE63u64];
+	for (i, chunk) in data.chunks(8).enumerate() {
+		let mut buf = [0u8; 8];
+		buf[..chunk.len()].copy_from_slice(chunk);
+		let lane = i % state.len();
+		state[lane] ^= u64::from_le_bytes(buf);
+		state[lane] = state[lane].wrapping_mul(0x100000001B3).rotate_left(13);
+	}
+	let mut out = [0u8; 32];
+	for (i, word) in state.iter().enumerate() {
+		out[i * 8..i * 8 + 8].copy_from_slice(&word.to_le_bytes());
+	}
+	out
+}
+
+fn hash_leaf<V: Encode>(key: &[u8], value: &V) -> Hash {
+	let mut bytes = Vec::with_capacity(key.len() + 32);
+	bytes.extend_from_slice(key);
+	bytes.extend_from_slice(&value.encode());
+	hash_bytes(&bytes)
+}
+
+fn hash_node(left: &Hash, right: &Hash) -> Hash {
+	let mut bytes = [0u8; 64];
+	bytes[..32].copy_from_slice(left);
+	bytes[32..].copy_from_slice(right);
+	hash_bytes(&bytes)
+}
+
+fn next_layer(layer: &[Hash]) -> Vec<Hash> {
+	let mut next = Vec::with_capacity((layer.len() + 1) / 2);
+	for pair in layer.chunks(2) {
+		if pair.len() == 2 {
+			next.push(hash_node(&pair[0], &pair[1]));
+		} else {
+			// Unpaired right edge: carried up unchanged.
+			next.push(pair[0]);
+		}
+	}
+	next
+}
+
+/// Append-only Merkle accumulator over the committed tier of an overlay,
+/// in the spirit of Libra's accumulator: leaves are `hash(key ||
+/// encode(value))` for each key that reaches `State::Committed`, and are
+/// only ever appended, never removed or reordered.
+#[derive(Debug, Clone, Default)]
+pub struct Accumulator {
+	leaves: Vec<Hash>,
+}
+
+impl Accumulator {
+	/// Record that `value` under `key` was just committed, appending its
+	/// leaf hash, and return the updated root.
+	pub fn commit<V: Encode>(&mut self, key: &[u8], value: &V) -> Hash {
+		self.leaves.push(hash_leaf(key, value));
+		self.accumulator_root()
+	}
+
+	/// Root hash over every leaf appended so far.
+	pub fn accumulator_root(&self) -> Hash {
+		let mut layer = self.leaves.clone();
+		while layer.len() > 1 {
+			layer = next_layer(&layer);
+		}
+		layer.get(0).copied().unwrap_or([0u8; 32])
+	}
+
+	/// Build an inclusion proof for the leaf at `index`, or `None` if
+	/// there is no such leaf.
+	pub fn prove(&self, index: usize) -> Option<AccumulatorProof> {
+		if index >= self.leaves.len() {
+			return None;
+		}
+		let mut siblings = Vec::new();
+		let mut layer = self.leaves.clone();
+		let mut pos = index;
+		while layer.len() > 1 {
+			let sibling_pos = pos ^ 1;
+			if sibling_pos < layer.len() {
+				siblings.push(layer[sibling_pos]);
+			}
+			layer = next_layer(&layer);
+			pos /= 2;
+		}
+		Some(AccumulatorProof { index, leaf_count: self.leaves.len(), siblings })
+	}
+}
+
+/// An inclusion proof against an `Accumulator` root: the ordered sibling
+/// hashes along the path from a leaf at `index` up to the root.
+///
+/// `leaf_count` pins down how many levels the path climbs and at which
+/// of them an unpaired right edge was carried up with no sibling, so
+/// `verify` can replay the same traversal as `Accumulator::prove` did.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AccumulatorProof {
+	index: usize,
+	leaf_count: usize,
+	siblings: Vec<Hash>,
+}
+
+/// Verify that `leaf` is included, at the position recorded in `proof`,
+/// under `root`: recompute the path by hashing the leaf with each
+/// sibling, choosing left/right order from the bits of the leaf index,
+/// and compare against `root`.
+pub fn verify(root: Hash, leaf: Hash, proof: &AccumulatorProof) -> bool {
+	let mut hash = leaf;
+	let mut pos = proof.index;
+	let mut level_size = proof.leaf_count;
+	let mut siblings = proof.siblings.iter();
+	while level_size > 1 {
+		let sibling_pos = pos ^ 1;
+		if sibling_pos < level_size {
+			let sibling = match siblings.next() {
+				Some(sibling) => sibling,
+				None => return false,
+			};
+			hash = if pos % 2 == 0 {
+				hash_node(&hash, sibling)
+			} else {
+				hash_node(sibling, &hash)
+			};
+		}
+		pos /= 2;
+		level_size = (level_size + 1) / 2;
+	}
+	siblings.next().is_none() && hash == root
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn history_of(entries: Vec<(State, u32)>) -> History<u32> {
+		History(entries.into_iter().map(|(index, value)| HistoricalValue { value, index }).collect())
+	}
+
+	/// A `States` whose `retained_entries` matches a `History` built
+	/// directly via `history_of`, so `apply_*` methods that debit removed
+	/// entries from it do not underflow.
+	fn states_for(current_layer: usize, retained: usize) -> States {
+		States { current_layer, config: Default::default(), retained_entries: Cell::new(retained) }
+	}
+
+	#[derive(Default)]
+	struct CountingListener {
+		prunes: usize,
+	}
+
+	impl HistoryListener<u32> for CountingListener {
+		fn on_prune(&mut self, _result: PruneResult) {
+			self.prunes += 1;
+		}
+	}
+
+	#[derive(Default)]
+	struct RecordingListener {
+		commits: Vec<usize>,
+		discards: Vec<usize>,
+		sets: Vec<(Option<u32>, u32)>,
+	}
+
+	impl HistoryListener<u32> for RecordingListener {
+		fn on_set<C>(&mut self, _key_ctx: &C, old: Option<&u32>, new: &u32) {
+			self.sets.push((old.copied(), *new));
+		}
+
+		fn on_commit(&mut self, layer: usize) {
+			self.commits.push(layer);
+		}
+
+		fn on_discard(&mut self, layer: usize) {
+			self.discards.push(layer);
+		}
+	}
+
+	#[test]
+	fn prune_committed_collapses_every_unreachable_layer() {
+		let mut history = history_of(vec![
+			(State::Committed, 1),
+			(State::Transaction(1), 2),
+			(State::Transaction(2), 3),
+			(State::Transaction(3), 4),
+		]);
+		let removed = history.prune_committed(0);
+		assert_eq!(removed, 3);
+		assert_eq!(history.0.len(), 1);
+		assert_eq!(history.0[0].index, State::Committed);
+		assert_eq!(history.0[0].value, 4);
+	}
+
+	#[test]
+	fn prune_committed_merges_into_existing_same_layer_entry() {
+		let mut history = history_of(vec![
+			(State::Transaction(1), 1),
+			(State::Transaction(2), 2),
+		]);
+		let removed = history.prune_committed(1);
+		assert_eq!(removed, 1);
+		assert_eq!(history.0.len(), 1);
+		assert_eq!(history.0[0].index, State::Transaction(1));
+		assert_eq!(history.0[0].value, 2);
+	}
+
+	#[test]
+	fn prune_committed_promotes_a_never_reopened_layer_zero_entry() {
+		// The value was only ever written at layer 0 and nothing was
+		// reopened above it, so the popping loop never runs and `carried`
+		// stays `None` — the top entry still has to be promoted in place.
+		let mut history = history_of(vec![(State::Transaction(0), 42)]);
+		let removed = history.prune_committed(0);
+		assert_eq!(removed, 0);
+		assert_eq!(history.0.len(), 1);
+		assert_eq!(history.0[0].index, State::Committed);
+		assert_eq!(history.0[0].value, 42);
+	}
+
+	#[test]
+	fn apply_commit_prospective_promotes_first_ever_write_at_layer_zero() {
+		let mut states = States::default();
+		let mut history = History::default();
+		history.set(&states, 7u32);
+		states.commit_prospective();
+		states.apply_commit_prospective(&mut history);
+		assert_eq!(history.get_committed(), Some(&7));
+		assert_eq!(history.into_committed(), Some(7));
+	}
+
+	#[test]
+	fn apply_discard_prospective_drops_the_discarded_layer() {
+		let mut states = states_for(1, 2);
+		let mut history = history_of(vec![
+			(State::Committed, 1),
+			(State::Transaction(1), 2),
+		]);
+		states.discard_prospective();
+		let result = states.apply_discard_prospective(&mut history);
+		assert_eq!(result, PruneResult::Kept);
+		assert_eq!(history.0.len(), 1);
+		assert_eq!(history.0[0].index, State::Committed);
+		assert_eq!(history.0[0].value, 1);
+	}
+
+	#[test]
+	fn apply_discard_prospective_clears_a_purely_prospective_key() {
+		let mut states = states_for(1, 1);
+		let mut history = history_of(vec![(State::Transaction(1), 2)]);
+		states.discard_prospective();
+		let result = states.apply_discard_prospective(&mut history);
+		assert_eq!(result, PruneResult::Cleared);
+		assert!(history.0.is_empty());
+	}
+
+	#[test]
+	fn apply_discard_transaction_drops_the_discarded_layer() {
+		let mut states = states_for(2, 2);
+		let mut history = history_of(vec![
+			(State::Transaction(1), 1),
+			(State::Transaction(2), 2),
+		]);
+		states.discard_transaction();
+		let result = states.apply_discard_transaction(&mut history);
+		assert_eq!(result, PruneResult::Kept);
+		assert_eq!(history.0.len(), 1);
+		assert_eq!(history.0[0].index, State::Transaction(1));
+		assert_eq!(history.0[0].value, 1);
+	}
+
+	#[test]
+	fn collapse_to_capacity_preserves_still_open_transaction() {
+		// Transaction(1) has not been committed yet: folding it into the
+		// committed slot would silently corrupt an in-flight transaction.
+		let mut history = history_of(vec![
+			(State::Committed, 1),
+			(State::Transaction(1), 2),
+		]);
+		let folded = history.collapse_to_capacity(2);
+		assert_eq!(folded, 0);
+		assert_eq!(history.0.len(), 2);
+	}
+
+	#[test]
+	fn collapse_to_capacity_folds_a_closed_layer_zero_entry() {
+		let mut history = history_of(vec![
+			(State::Committed, 1),
+			(State::Transaction(0), 2),
+			(State::Transaction(1), 3),
+		]);
+		let folded = history.collapse_to_capacity(2);
+		assert_eq!(folded, 1);
+		assert_eq!(history.0.len(), 2);
+		assert_eq!(history.0[0].index, State::Committed);
+		assert_eq!(history.0[0].value, 2);
+	}
+
+	#[test]
+	fn states_max_layers_reflects_config() {
+		let states = States::with_config(StatesConfig { max_layers: Some(4), max_history_per_key: None });
+		assert_eq!(states.max_layers(), Some(4));
+	}
+
+	#[test]
+	fn apply_commit_prospective_without_listener_compiles_and_works() {
+		let mut states = States::test_state(1);
+		let mut history = History::default();
+		history.set(&states, 1u32);
+		states.commit_prospective();
+		let result = states.apply_commit_prospective(&mut history);
+		assert_eq!(result, PruneResult::Kept);
+		assert_eq!(history.get(), Some(&1));
+	}
+
+	#[test]
+	fn set_with_listener_notifies_on_prune_when_capacity_folds() {
+		let config = StatesConfig { max_layers: None, max_history_per_key: Some(2) };
+		let states = States { current_layer: 1, config, retained_entries: Cell::new(0) };
+		let mut history = history_of(vec![
+			(State::Committed, 1),
+			(State::Transaction(0), 2),
+		]);
+		let mut listener = CountingListener::default();
+		history.set_with_listener(&states, &(), 3u32, Some(&mut listener));
+		assert_eq!(listener.prunes, 1, "capacity fold should notify the listener");
+		assert_eq!(history.0.len(), 2);
+		assert_eq!(history.0[0].index, State::Committed);
+		assert_eq!(history.0[0].value, 2);
+		assert_eq!(history.0[1].index, State::Transaction(1));
+		assert_eq!(history.0[1].value, 3);
+	}
+
+	#[test]
+	fn apply_commit_prospective_with_listener_notifies_on_commit() {
+		let states = states_for(1, 1);
+		let mut history = history_of(vec![(State::Transaction(1), 1)]);
+		let mut listener = RecordingListener::default();
+		states.apply_commit_prospective_with_listener(&mut history, Some(&mut listener));
+		assert_eq!(listener.commits, vec![1]);
+	}
+
+	#[test]
+	fn apply_discard_transaction_with_listener_notifies_on_discard() {
+		let states = states_for(1, 2);
+		let mut history = history_of(vec![
+			(State::Transaction(1), 1),
+			(State::Transaction(2), 2),
+		]);
+		let mut listener = RecordingListener::default();
+		states.apply_discard_transaction_with_listener(&mut history, Some(&mut listener));
+		assert_eq!(listener.discards, vec![2]);
+	}
+
+	#[test]
+	fn set_with_listener_reports_old_value_and_none_only_on_first_write() {
+		let states = states_for(0, 0);
+		let mut history = History::default();
+		let mut listener = RecordingListener::default();
+		history.set_with_listener(&states, &(), 1u32, Some(&mut listener));
+		assert_eq!(listener.sets, vec![(None, 1)]);
+
+		history.set_with_listener(&states, &(), 2u32, Some(&mut listener));
+		assert_eq!(listener.sets, vec![(None, 1), (Some(1), 2)]);
+
+		let states = states_for(1, 1);
+		history.set_with_listener(&states, &(), 3u32, Some(&mut listener));
+		assert_eq!(listener.sets, vec![(None, 1), (Some(1), 2), (Some(2), 3)]);
+	}
+
+	#[test]
+	fn history_canonical_round_trip() {
+		let history = history_of(vec![
+			(State::Committed, 1),
+			(State::Transaction(2), 2),
+			(State::Transaction(3), 3),
+		]);
+		let encoded = history.encode_canonical();
+		let decoded = History::<u32>::decode_canonical(&encoded).unwrap();
+		assert_eq!(decoded, history);
+	}
+
+	#[test]
+	fn states_canonical_round_trip() {
+		let states = States::with_config(StatesConfig { max_layers: Some(4), max_history_per_key: Some(8) });
+		let encoded = states.encode_canonical();
+		let decoded = States::decode_canonical(&encoded).unwrap();
+		assert_eq!(decoded, states);
+	}
+
+	#[test]
+	fn apply_commit_transaction_into_accumulator_feeds_committed_value() {
+		let mut states = States::test_state(1);
+		let mut history = History::default();
+		history.set(&states, 5u32);
+		states.commit_transaction();
+		let mut accumulator = Accumulator::default();
+		let result = states.apply_commit_transaction_into_accumulator::<u32, NoopListener>(
+			&mut history, b"key", &mut accumulator, None,
+		);
+		assert_eq!(result, PruneResult::Kept);
+		assert_eq!(history.get_committed(), Some(&5));
+		assert_eq!(accumulator.accumulator_root(), hash_leaf(b"key", &5u32));
+	}
+
+	#[test]
+	fn accumulator_proof_round_trip() {
+		let mut accumulator = Accumulator::default();
+		let values: [u32; 3] = [1, 2, 3];
+		let keys: [&[u8]; 3] = [b"a", b"b", b"c"];
+		for (key, value) in keys.iter().zip(values.iter()) {
+			accumulator.commit(*key, value);
+		}
+		let root = accumulator.accumulator_root();
+		for i in 0..values.len() {
+			let leaf = hash_leaf(keys[i], &values[i]);
+			let proof = accumulator.prove(i).unwrap();
+			assert!(verify(root, leaf, &proof));
+		}
+	}
+
+	#[test]
+	fn accumulator_proof_rejects_wrong_leaf() {
+		let mut accumulator = Accumulator::default();
+		accumulator.commit(b"a", &1u32);
+		accumulator.commit(b"b", &2u32);
+		let root = accumulator.accumulator_root();
+		let proof = accumulator.prove(0).unwrap();
+		let wrong_leaf = hash_leaf(b"a", &99u32);
+		assert!(!verify(root, wrong_leaf, &proof));
+	}
+
+	#[test]
+	fn iter_pending_walks_every_layer_oldest_first() {
+		let history = history_of(vec![
+			(State::Committed, 1),
+			(State::Transaction(2), 2),
+			(State::Transaction(5), 3),
+		]);
+		let collected: Vec<_> = history.iter_pending().collect();
+		assert_eq!(collected, vec![(0, &1), (2, &2), (5, &3)]);
+	}
+
+	#[test]
+	fn iter_layer_returns_the_value_visible_at_that_depth() {
+		let history = history_of(vec![
+			(State::Committed, 1),
+			(State::Transaction(2), 2),
+			(State::Transaction(5), 3),
+		]);
+		assert_eq!(history.iter_layer(0), Some(&1));
+		assert_eq!(history.iter_layer(1), Some(&1));
+		assert_eq!(history.iter_layer(2), Some(&2));
+		assert_eq!(history.iter_layer(4), Some(&2));
+		assert_eq!(history.iter_layer(5), Some(&3));
+	}
 }